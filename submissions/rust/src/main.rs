@@ -1,18 +1,72 @@
 use csv::{Reader, WriterBuilder};
-use lightning_invoice::Bolt11Invoice;
+use lightning_invoice::{Bolt11Invoice, RouteHintHop};
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::path::Path;
 use std::str::FromStr;
 
 // hop in the payment route
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 struct RouteHop {
     path_id: u32,
     channel_name: String,
     cltv_delta: u32,
     base_fee_msat: u64,
     proportional_fee_ppm: u64,
+    htlc_minimum_msat: u64,
+    htlc_maximum_msat: u64,
+    // the following are only set on the introduction-node hop of a path that
+    // terminates in a blinded recipient (BOLT12/offer-style destination)
+    #[serde(default)]
+    blinded_fee_base_msat: Option<u32>,
+    #[serde(default)]
+    blinded_fee_proportional_millionths: Option<u32>,
+    #[serde(default)]
+    blinded_cltv_expiry_delta: Option<u16>,
+    #[serde(default)]
+    blinded_htlc_minimum_msat: Option<u64>,
+}
+
+// aggregate fee/cltv accounting for the blinded segment appended after an
+// introduction node, mirroring LDK's BlindedPayInfo
+#[derive(Debug, Clone, Copy)]
+struct BlindedPayInfo {
+    fee_base_msat: u32,
+    fee_proportional_millionths: u32,
+    cltv_expiry_delta: u16,
+    htlc_minimum_msat: u64,
+}
+
+impl BlindedPayInfo {
+    // only the introduction-node hop carries blinded fields, and only when all of them are set
+    fn from_hop(hop: &RouteHop) -> Option<Self> {
+        Some(BlindedPayInfo {
+            fee_base_msat: hop.blinded_fee_base_msat?,
+            fee_proportional_millionths: hop.blinded_fee_proportional_millionths?,
+            cltv_expiry_delta: hop.blinded_cltv_expiry_delta?,
+            htlc_minimum_msat: hop.blinded_htlc_minimum_msat?,
+        })
+    }
+
+    // amount the introduction node must receive so that `final_amount_msat` is
+    // delivered at the end of the blinded path
+    fn inflate_amount(&self, final_amount_msat: u64) -> u64 {
+        final_amount_msat
+            + self.fee_base_msat as u64
+            + (final_amount_msat * self.fee_proportional_millionths as u64) / 1_000_000
+    }
+
+    // the blinded path itself rejects any HTLC below its advertised minimum
+    fn check_minimum(&self, path_id: u32, path_amount_msat: u64) -> Result<(), Box<dyn std::error::Error>> {
+        if path_amount_msat < self.htlc_minimum_msat {
+            return Err(format!(
+                "path {}: final delivered amount {} msat is below blinded htlc_minimum_msat {}",
+                path_id, path_amount_msat, self.htlc_minimum_msat
+            )
+            .into());
+        }
+        Ok(())
+    }
 }
 
 // calculate the HTLC values for a hop
@@ -30,30 +84,429 @@ fn calculate_fee(amount_msat: u64, base_fee_msat: u64, proportional_fee_ppm: u64
     base_fee_msat + (amount_msat * proportional_fee_ppm) / 1_000_000
 }
 
-// create the tlv record
-fn create_mpp_tlv(payment_secret: &[u8], total_msat: u64) -> String {
-    let mut tlv = Vec::new();
+// BOLT-4 BigSize varint encoding
+fn encode_bigsize(value: u64) -> Vec<u8> {
+    if value < 0xfd {
+        vec![value as u8]
+    } else if value < 0x10000 {
+        let mut out = vec![0xfd];
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+        out
+    } else if value < 0x1_0000_0000 {
+        let mut out = vec![0xfe];
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+        out
+    } else {
+        let mut out = vec![0xff];
+        out.extend_from_slice(&value.to_be_bytes());
+        out
+    }
+}
+
+// truncated-integer encoding used by the tuXX TLV fields: big-endian with
+// leading zero bytes stripped (a zero value encodes as zero-length)
+fn truncate_be(bytes: &[u8]) -> &[u8] {
+    match bytes.iter().position(|&b| b != 0) {
+        Some(i) => &bytes[i..],
+        None => &[],
+    }
+}
+
+// a single TLV record: BigSize(type) || BigSize(length) || value
+fn encode_tlv_record(tlv_type: u64, value: &[u8]) -> Vec<u8> {
+    let mut record = encode_bigsize(tlv_type);
+    record.extend(encode_bigsize(value.len() as u64));
+    record.extend_from_slice(value);
+    record
+}
+
+// build the onion payload for one hop: amt_to_forward (2) and
+// outgoing_cltv_value (4) always, short_channel_id (6) for non-final hops,
+// and payment_data (8) for the final hop of an MPP, in ascending type order
+fn build_hop_tlv_stream(
+    amt_to_forward: u64,
+    outgoing_cltv_value: u32,
+    short_channel_id: Option<u64>,
+    payment_data: Option<(&[u8], u64)>,
+) -> String {
+    let mut stream = Vec::new();
+
+    stream.extend(encode_tlv_record(
+        2,
+        truncate_be(&amt_to_forward.to_be_bytes()),
+    ));
+    stream.extend(encode_tlv_record(
+        4,
+        truncate_be(&outgoing_cltv_value.to_be_bytes()),
+    ));
+
+    if let Some(scid) = short_channel_id {
+        stream.extend(encode_tlv_record(6, &scid.to_be_bytes()));
+    }
+
+    if let Some((payment_secret, total_msat)) = payment_data {
+        let mut value = Vec::with_capacity(32 + 8);
+        value.extend_from_slice(payment_secret);
+        value.extend_from_slice(truncate_be(&total_msat.to_be_bytes()));
+        stream.extend(encode_tlv_record(8, &value));
+    }
+
+    stream.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// channel_name is expected to encode the numeric short_channel_id as a decimal
+// string, optionally prefixed with "hint:" for hops synthesized from a BOLT11
+// invoice route hint
+fn parse_short_channel_id(channel_name: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let scid_str = channel_name.strip_prefix("hint:").unwrap_or(channel_name);
+    scid_str.parse::<u64>().map_err(|_| {
+        format!(
+            "channel_name '{}' is not a valid short_channel_id",
+            channel_name
+        )
+        .into()
+    })
+}
 
-    // type (8) - 8 bytes
-    tlv.extend_from_slice(&8u64.to_be_bytes());
+// turn an invoice-embedded route-hint hop into a RouteHop so it folds into
+// the backward fee/expiry pass exactly like a CSV hop. path_id is a
+// placeholder (0) - assign_route_hints stamps the real target path_id in
+// once it knows which path the hint is being folded into.
+fn route_hop_from_hint(hint_hop: &RouteHintHop) -> RouteHop {
+    RouteHop {
+        path_id: 0,
+        channel_name: format!("hint:{}", hint_hop.short_channel_id),
+        cltv_delta: hint_hop.cltv_expiry_delta as u32,
+        base_fee_msat: hint_hop.fees.base_msat as u64,
+        proportional_fee_ppm: hint_hop.fees.proportional_millionths as u64,
+        htlc_minimum_msat: hint_hop.htlc_minimum_msat.unwrap_or(0),
+        htlc_maximum_msat: hint_hop.htlc_maximum_msat.unwrap_or(u64::MAX),
+        blinded_fee_base_msat: None,
+        blinded_fee_proportional_millionths: None,
+        blinded_cltv_expiry_delta: None,
+        blinded_htlc_minimum_msat: None,
+    }
+}
 
-    // length (40) - 8 bytes
-    tlv.extend_from_slice(&40u64.to_be_bytes());
+// fold invoice route hints onto the tail of each path. Each BOLT11 `r` field
+// is an independent candidate last-mile route, not a chain to splice
+// together, so match at most one hint per path (round-robin by path_id) -
+// never concatenate every hint onto every path's tail. Hint hops must be
+// inserted before the blinded introduction node (if any), not appended after
+// it, or they'd become the new last hop and hide the blinded path from the
+// BlindedPayInfo::from_hop lookup in main.
+fn assign_route_hints(paths: &mut std::collections::HashMap<u32, Vec<RouteHop>>, hint_paths: &[Vec<RouteHop>]) {
+    if hint_paths.is_empty() {
+        return;
+    }
 
-    // payment secret - 32 bytes
-    tlv.extend_from_slice(payment_secret);
+    let mut path_ids: Vec<u32> = paths.keys().copied().collect();
+    path_ids.sort_unstable();
+    for (i, path_id) in path_ids.into_iter().enumerate() {
+        let hint_hops = &hint_paths[i % hint_paths.len()];
+        let path_hops = paths.get_mut(&path_id).unwrap();
+        let insert_at = path_hops
+            .iter()
+            .position(|hop| BlindedPayInfo::from_hop(hop).is_some())
+            .unwrap_or(path_hops.len());
+        for (offset, hint_hop) in hint_hops.iter().enumerate() {
+            let mut hop = hint_hop.clone();
+            hop.path_id = path_id;
+            path_hops.insert(insert_at + offset, hop);
+        }
+    }
+}
 
-    // total amount in millisatoshis - 8 bytes
-    tlv.extend_from_slice(&total_msat.to_be_bytes());
+// the HTLC bounds a path can actually carry: the largest per-hop minimum and
+// the smallest per-hop maximum are the binding constraints along the path
+struct PathCapacity {
+    path_min: u64,
+    path_max: u64,
+}
 
-    // convert to hex string
-    tlv.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+fn path_capacity(path_hops: &[RouteHop]) -> PathCapacity {
+    PathCapacity {
+        path_min: path_hops.iter().map(|h| h.htlc_minimum_msat).max().unwrap_or(0),
+        path_max: path_hops.iter().map(|h| h.htlc_maximum_msat).min().unwrap_or(0),
+    }
+}
+
+// split payment_amount_msat across paths in proportion to their capacity,
+// clamped to each path's [htlc_minimum_msat, htlc_maximum_msat], dropping any
+// path that can't even carry its own minimum and redistributing the rest
+fn split_payment_amount(
+    payment_amount_msat: u64,
+    paths: &std::collections::HashMap<u32, Vec<RouteHop>>,
+) -> Result<std::collections::HashMap<u32, u64>, Box<dyn std::error::Error>> {
+    let mut capacities: std::collections::HashMap<u32, PathCapacity> = paths
+        .iter()
+        .map(|(&path_id, hops)| (path_id, path_capacity(hops)))
+        .collect();
+
+    loop {
+        let mut path_ids: Vec<u32> = capacities.keys().copied().collect();
+        path_ids.sort_unstable();
+
+        let total_capacity: u64 = capacities.values().map(|c| c.path_max).sum();
+        if total_capacity < payment_amount_msat {
+            return Err(format!(
+                "total path capacity {} msat cannot carry payment of {} msat",
+                total_capacity, payment_amount_msat
+            )
+            .into());
+        }
+
+        let mut shares: std::collections::HashMap<u32, u64> = path_ids
+            .iter()
+            .map(|&path_id| {
+                let capacity = &capacities[&path_id];
+                let share = (payment_amount_msat as u128 * capacity.path_max as u128
+                    / total_capacity as u128) as u64;
+                (path_id, share.min(capacity.path_max))
+            })
+            .collect();
+
+        // a path whose proportional share can't meet its own minimum, or that
+        // has no usable capacity at all (path_max == 0, e.g. a channel with
+        // htlc_maximum_msat=0), can't carry any part of this payment; drop it
+        // and re-split among the rest
+        let starved: Vec<u32> = path_ids
+            .iter()
+            .copied()
+            .filter(|path_id| {
+                let capacity = &capacities[path_id];
+                capacity.path_max == 0 || shares[path_id] < capacity.path_min
+            })
+            .collect();
+        if !starved.is_empty() {
+            for path_id in starved {
+                capacities.remove(&path_id);
+            }
+            if capacities.is_empty() {
+                return Err("no path can carry the minimum HTLC for this payment".into());
+            }
+            continue;
+        }
+
+        // redistribute any rounding residual to paths with remaining headroom
+        let mut residual = payment_amount_msat - shares.values().sum::<u64>();
+        while residual > 0 {
+            let mut placed_any = false;
+            for &path_id in &path_ids {
+                if residual == 0 {
+                    break;
+                }
+                let headroom = capacities[&path_id].path_max - shares[&path_id];
+                if headroom == 0 {
+                    continue;
+                }
+                let take = headroom.min(residual);
+                *shares.get_mut(&path_id).unwrap() += take;
+                residual -= take;
+                placed_any = true;
+            }
+            if !placed_any {
+                return Err("insufficient headroom to place the full payment amount".into());
+            }
+        }
+
+        return Ok(shares);
+    }
+}
+
+// one directed channel in the network graph, used by the graph-based
+// path-finding mode (--graph) instead of a fixed RouteHop path
+#[derive(Debug, Deserialize, Clone)]
+struct GraphEdge {
+    src_node: String,
+    dst_node: String,
+    channel_name: String,
+    capacity_msat: u64,
+    base_fee_msat: u64,
+    proportional_fee_ppm: u64,
+    cltv_delta: u32,
+    htlc_minimum_msat: u64,
+    htlc_maximum_msat: u64,
+}
+
+// a synthetic weight converting cltv_delta blocks into the same msat scale as
+// the fee and liquidity penalties, so the three terms can be added directly
+const CLTV_PENALTY_MSAT_PER_BLOCK: u64 = 100;
+
+// grows sharply as the routed amount approaches the channel's capacity, so
+// near-saturated channels are avoided even when their fee is small
+fn liquidity_penalty_msat(amount_msat: u64, capacity_msat: u64) -> u64 {
+    let headroom = (capacity_msat - amount_msat) as f64 / capacity_msat as f64;
+    let penalty = -headroom.ln() * amount_msat as f64;
+    if penalty.is_finite() && penalty > 0.0 {
+        penalty as u64
+    } else {
+        0
+    }
+}
+
+// cost of routing amount_msat over this channel, or None if it can't carry
+// it at all; `reused` channels are penalized so a second search prefers
+// disjoint capacity over re-using a channel already claimed by another path
+fn edge_cost_msat(edge: &GraphEdge, amount_msat: u64, reused: bool) -> Option<u64> {
+    if amount_msat < edge.htlc_minimum_msat
+        || amount_msat > edge.htlc_maximum_msat
+        || amount_msat >= edge.capacity_msat
+    {
+        return None;
+    }
+
+    let fee = calculate_fee(amount_msat, edge.base_fee_msat, edge.proportional_fee_ppm);
+    let cltv_penalty = edge.cltv_delta as u64 * CLTV_PENALTY_MSAT_PER_BLOCK;
+    let liquidity_penalty = liquidity_penalty_msat(amount_msat, edge.capacity_msat);
+    let cost = fee + cltv_penalty + liquidity_penalty;
+
+    Some(if reused { cost.saturating_mul(1_000) } else { cost })
+}
+
+// Dijkstra-style search run backward from the destination: each relaxed edge
+// also carries forward the amount that must arrive on the node's far side,
+// so amounts inflate hop by hop exactly as they will once the path is built
+fn find_path(
+    edges: &[GraphEdge],
+    source: &str,
+    destination: &str,
+    amount_msat: u64,
+    used_channels: &std::collections::HashSet<String>,
+) -> Option<Vec<GraphEdge>> {
+    let mut incoming: std::collections::HashMap<&str, Vec<&GraphEdge>> = std::collections::HashMap::new();
+    for edge in edges {
+        incoming.entry(edge.dst_node.as_str()).or_default().push(edge);
+    }
+
+    let mut dist: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
+    let mut amount_at: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
+    let mut prev: std::collections::HashMap<String, GraphEdge> = std::collections::HashMap::new();
+    let mut heap: std::collections::BinaryHeap<std::cmp::Reverse<(u64, String)>> =
+        std::collections::BinaryHeap::new();
+
+    dist.insert(destination.to_string(), 0);
+    amount_at.insert(destination.to_string(), amount_msat);
+    heap.push(std::cmp::Reverse((0, destination.to_string())));
+
+    while let Some(std::cmp::Reverse((cost_so_far, node))) = heap.pop() {
+        if node == source {
+            break;
+        }
+        if cost_so_far > *dist.get(&node).unwrap_or(&u64::MAX) {
+            continue;
+        }
+        let downstream_amount = amount_at[&node];
+
+        if let Some(in_edges) = incoming.get(node.as_str()) {
+            for edge in in_edges {
+                let reused = used_channels.contains(&edge.channel_name);
+                if let Some(edge_cost) = edge_cost_msat(edge, downstream_amount, reused) {
+                    let fee = calculate_fee(
+                        downstream_amount,
+                        edge.base_fee_msat,
+                        edge.proportional_fee_ppm,
+                    );
+                    let upstream_amount = downstream_amount + fee;
+                    let next_cost = cost_so_far + edge_cost;
+
+                    if next_cost < *dist.get(&edge.src_node).unwrap_or(&u64::MAX) {
+                        dist.insert(edge.src_node.clone(), next_cost);
+                        amount_at.insert(edge.src_node.clone(), upstream_amount);
+                        prev.insert(edge.src_node.clone(), (*edge).clone());
+                        heap.push(std::cmp::Reverse((next_cost, edge.src_node.clone())));
+                    }
+                }
+            }
+        }
+    }
+
+    if source != destination && !prev.contains_key(source) {
+        return None;
+    }
+
+    // walk the predecessor chain back to forward order: source's own hop first
+    let mut path = Vec::new();
+    let mut current = source.to_string();
+    while current != destination {
+        let edge = prev.get(&current)?;
+        path.push(edge.clone());
+        current = edge.dst_node.clone();
+    }
+    Some(path)
+}
+
+// the best path, plus up to k-1 additional near-disjoint paths for MPP found
+// by inflating the cost of already-claimed channels and re-running the search
+fn find_mpp_paths(
+    edges: &[GraphEdge],
+    source: &str,
+    destination: &str,
+    payment_amount_msat: u64,
+    k: usize,
+) -> Result<Vec<Vec<GraphEdge>>, Box<dyn std::error::Error>> {
+    let mut used_channels: std::collections::HashSet<String> = std::collections::HashSet::new();
+    let mut paths = Vec::new();
+
+    // search for a path that can carry its anticipated MPP share rather than
+    // the full payment, since the whole point of splitting across k paths is
+    // that no single channel may have capacity for the full amount
+    let per_path_amount_msat = payment_amount_msat / k.max(1) as u64;
+
+    let best = find_path(edges, source, destination, per_path_amount_msat, &used_channels)
+        .ok_or("no path found from source to destination for the requested amount")?;
+    for edge in &best {
+        used_channels.insert(edge.channel_name.clone());
+    }
+    paths.push(best);
+
+    for _ in 1..k {
+        match find_path(edges, source, destination, per_path_amount_msat, &used_channels) {
+            Some(path) => {
+                for edge in &path {
+                    used_channels.insert(edge.channel_name.clone());
+                }
+                paths.push(path);
+            }
+            None => break,
+        }
+    }
+
+    Ok(paths)
+}
+
+// feed a graph edge into the existing backward amount/expiry and TLV machinery
+fn route_hop_from_edge(path_id: u32, edge: &GraphEdge) -> RouteHop {
+    RouteHop {
+        path_id,
+        channel_name: edge.channel_name.clone(),
+        cltv_delta: edge.cltv_delta,
+        base_fee_msat: edge.base_fee_msat,
+        proportional_fee_ppm: edge.proportional_fee_ppm,
+        htlc_minimum_msat: edge.htlc_minimum_msat,
+        htlc_maximum_msat: edge.htlc_maximum_msat,
+        blinded_fee_base_msat: None,
+        blinded_fee_proportional_millionths: None,
+        blinded_cltv_expiry_delta: None,
+        blinded_htlc_minimum_msat: None,
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Parse command line arguments
+    // Parse command line arguments. Passing "--graph" as the second argument
+    // switches to the graph-based path-finding mode; otherwise the CSV lists
+    // fixed paths as before.
     let args: Vec<String> = env::args().collect();
-    if args.len() != 5 {
+    let graph_mode = args.get(2).map(String::as_str) == Some("--graph");
+
+    if graph_mode && args.len() != 9 {
+        eprintln!(
+            "Usage: {} <output_dir> --graph <graph_csv> <source_node> <destination_node> <k> <payment_request> <block_height>",
+            args[0]
+        );
+        std::process::exit(1);
+    }
+    if !graph_mode && args.len() != 5 {
         // here the first arg is the cli itself
         eprintln!(
             "Usage: {} <output_dir> <input_csv> <payment_request> <block_height>",
@@ -63,9 +516,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let output_dir = &args[1];
-    let input_csv = &args[2];
-    let payment_request = &args[3];
-    let current_height: u32 = args[4].parse()?;
+    let (payment_request, current_height) = if graph_mode {
+        (&args[7], args[8].parse::<u32>()?)
+    } else {
+        (&args[3], args[4].parse::<u32>()?)
+    };
 
     // parse the payment invoice
     let invoice = Bolt11Invoice::from_str(payment_request)
@@ -77,17 +532,45 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // retriev min final cltv from invoice
     let min_final_cltv_delta = invoice.min_final_cltv_expiry_delta() as u32;
 
-    // read the input file
-    let mut rdr = Reader::from_path(input_csv)?;
-    let hops: Vec<RouteHop> = rdr.deserialize().collect::<Result<_, _>>()?;
-
     let mut paths: std::collections::HashMap<u32, Vec<RouteHop>> = std::collections::HashMap::new();
-    for hop in hops {
-        paths.entry(hop.path_id).or_insert_with(Vec::new).push(hop);
+
+    if graph_mode {
+        let graph_csv = &args[3];
+        let source_node = &args[4];
+        let destination_node = &args[5];
+        let k: usize = args[6].parse()?;
+
+        let mut rdr = Reader::from_path(graph_csv)?;
+        let edges: Vec<GraphEdge> = rdr.deserialize().collect::<Result<_, _>>()?;
+
+        let found_paths =
+            find_mpp_paths(&edges, source_node, destination_node, payment_amount_msat, k)?;
+        for (path_id, path_edges) in found_paths.into_iter().enumerate() {
+            let route_hops = path_edges
+                .iter()
+                .map(|edge| route_hop_from_edge(path_id as u32, edge))
+                .collect();
+            paths.insert(path_id as u32, route_hops);
+        }
+    } else {
+        // read the input file
+        let input_csv = &args[2];
+        let mut rdr = Reader::from_path(input_csv)?;
+        let hops: Vec<RouteHop> = rdr.deserialize().collect::<Result<_, _>>()?;
+        for hop in hops {
+            paths.entry(hop.path_id).or_default().push(hop);
+        }
     }
 
-    let path_count = paths.len() as u64;
-    let base_amount_per_path = payment_amount_msat / path_count;
+    // private last-mile channels embedded in the invoice aren't in the CSV
+    let hint_paths: Vec<Vec<RouteHop>> = invoice
+        .route_hints()
+        .iter()
+        .map(|hint| hint.0.iter().map(route_hop_from_hint).collect())
+        .collect();
+    assign_route_hints(&mut paths, &hint_paths);
+
+    let path_amounts = split_payment_amount(payment_amount_msat, &paths)?;
 
     // let's create the output file
     let output_path = Path::new(output_dir).join("output.csv");
@@ -97,11 +580,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut all_htlc_outputs: Vec<HtlcOutput> = Vec::new();
 
-    // process each path
-    for (path_id, path_hops) in paths.iter() {
-        let mut current_amount = base_amount_per_path;
+    // process each path that survived the capacity-aware split
+    for (path_id, path_amount) in path_amounts.iter() {
+        let path_hops = &paths[path_id];
+        let mut current_amount = *path_amount;
         let mut current_expiry = current_height;
 
+        // a path ending in a blinded recipient carries the aggregate
+        // BlindedPayInfo on its introduction-node (last) hop
+        let blinded_info = path_hops.last().and_then(BlindedPayInfo::from_hop);
+        if let Some(info) = &blinded_info {
+            info.check_minimum(*path_id, *path_amount)?;
+        }
+
         // calculate fees and amounts backwards
         let mut amounts_and_expiries: Vec<(u64, u32)> = Vec::new();
 
@@ -110,23 +601,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mut prev_proportional_fee_ppm = 0;
 
         for (index, hop) in path_hops.iter().rev().enumerate() {
-            // for the final hop we need to add the min_final_cltv_delta
-            let cltv_delta = if index == 0 {
-                min_final_cltv_delta
+            if index == 0 {
+                // for the final hop, the blinded tail (if any) is folded in as a
+                // single virtual hop attached after the introduction node
+                if let Some(info) = &blinded_info {
+                    current_amount = info.inflate_amount(current_amount);
+                    current_expiry += min_final_cltv_delta + info.cltv_expiry_delta as u32;
+                } else {
+                    current_expiry += min_final_cltv_delta;
+                }
             } else {
-                prev_cltv_delta
-            };
-            prev_cltv_delta = hop.cltv_delta;
-
-            current_expiry += cltv_delta;
+                current_expiry += prev_cltv_delta;
 
-            // calculate fee for intermediate hops
-            if index > 0 {
+                // calculate fee for intermediate hops
                 let fee =
                     calculate_fee(current_amount, prev_amount_msat, prev_proportional_fee_ppm);
                 current_amount += fee;
             }
 
+            prev_cltv_delta = hop.cltv_delta;
             prev_proportional_fee_ppm = hop.proportional_fee_ppm;
 
             prev_amount_msat = hop.base_fee_msat;
@@ -137,12 +630,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         // write hltc values for each hop
         for (i, (hop, (amount, expiry))) in path_hops.iter().zip(amounts_and_expiries).enumerate() {
-            let tlv = if i == path_hops.len() - 1 && paths.len() > 1 {
-                create_mpp_tlv(invoice.payment_secret().0.as_slice(), payment_amount_msat)
+            let is_final_hop = i == path_hops.len() - 1;
+
+            // the final hop delivers to the recipient rather than forwarding
+            // over a channel, so it carries no short_channel_id
+            let short_channel_id = if is_final_hop {
+                None
+            } else {
+                Some(parse_short_channel_id(&hop.channel_name)?)
+            };
+
+            let payment_data = if is_final_hop && path_amounts.len() > 1 {
+                Some((invoice.payment_secret().0.as_slice(), payment_amount_msat))
             } else {
-                "NULL".to_string()
+                None
             };
 
+            let tlv = build_hop_tlv_stream(amount, expiry, short_channel_id, payment_data);
+
             all_htlc_outputs.push(HtlcOutput {
                 path_id: *path_id,
                 channel_name: hop.channel_name.clone(),
@@ -164,3 +669,270 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     wtr.flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bigsize_encodes_each_size_class() {
+        assert_eq!(encode_bigsize(0), vec![0x00]);
+        assert_eq!(encode_bigsize(0xfc), vec![0xfc]);
+        assert_eq!(encode_bigsize(0xfd), vec![0xfd, 0x00, 0xfd]);
+        assert_eq!(encode_bigsize(0xffff), vec![0xfd, 0xff, 0xff]);
+        assert_eq!(encode_bigsize(0x10000), vec![0xfe, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(
+            encode_bigsize(0x1_0000_0000),
+            vec![0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn hop_tlv_stream_omits_absent_fields() {
+        let tlv = build_hop_tlv_stream(1, 2, None, None);
+        assert_eq!(tlv, "020101040102");
+    }
+
+    #[test]
+    fn hop_tlv_stream_orders_scid_and_payment_data_ascending() {
+        let secret = [0x11u8; 32];
+        let tlv = build_hop_tlv_stream(100, 40, Some(1), Some((&secret, 300)));
+        assert_eq!(
+            tlv,
+            "0201640401280608000000000000000108221111111111111111111111111111111111111111111111111111111111111111012c"
+        );
+    }
+
+    // a single-hop path whose capacity is exactly [htlc_minimum_msat, htlc_maximum_msat]
+    fn single_hop_path(path_id: u32, htlc_minimum_msat: u64, htlc_maximum_msat: u64) -> Vec<RouteHop> {
+        vec![RouteHop {
+            path_id,
+            channel_name: format!("{}", path_id),
+            cltv_delta: 40,
+            base_fee_msat: 1000,
+            proportional_fee_ppm: 1,
+            htlc_minimum_msat,
+            htlc_maximum_msat,
+            blinded_fee_base_msat: None,
+            blinded_fee_proportional_millionths: None,
+            blinded_cltv_expiry_delta: None,
+            blinded_htlc_minimum_msat: None,
+        }]
+    }
+
+    #[test]
+    fn split_divides_proportionally_to_capacity() {
+        let paths = std::collections::HashMap::from([
+            (0, single_hop_path(0, 0, 30_000)),
+            (1, single_hop_path(1, 0, 70_000)),
+        ]);
+
+        let shares = split_payment_amount(100_000, &paths).unwrap();
+
+        assert_eq!(shares.values().sum::<u64>(), 100_000);
+        assert_eq!(shares[&0], 30_000);
+        assert_eq!(shares[&1], 70_000);
+    }
+
+    #[test]
+    fn split_drops_starved_path_and_redistributes_remainder() {
+        // path 0 can only carry up to 1_000 msat, below the 50_000 msat
+        // minimum it would need just to be touched at all, so it must be
+        // dropped entirely and path 1 takes the whole payment
+        let paths = std::collections::HashMap::from([
+            (0, single_hop_path(0, 50_000, 1_000)),
+            (1, single_hop_path(1, 0, 100_000)),
+        ]);
+
+        let shares = split_payment_amount(100_000, &paths).unwrap();
+
+        assert_eq!(shares.len(), 1);
+        assert_eq!(shares[&1], 100_000);
+    }
+
+    #[test]
+    fn split_fails_when_total_capacity_is_insufficient() {
+        let paths = std::collections::HashMap::from([
+            (0, single_hop_path(0, 0, 30_000)),
+            (1, single_hop_path(1, 0, 40_000)),
+        ]);
+
+        let result = split_payment_amount(100_000, &paths);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn split_drops_zero_capacity_path() {
+        // path 0 has htlc_maximum_msat=0 (no usable liquidity); it must be
+        // dropped rather than surviving with a 0 msat share
+        let paths = std::collections::HashMap::from([
+            (0, single_hop_path(0, 0, 0)),
+            (1, single_hop_path(1, 0, 100_000)),
+        ]);
+
+        let shares = split_payment_amount(100_000, &paths).unwrap();
+
+        assert_eq!(shares.len(), 1);
+        assert_eq!(shares[&1], 100_000);
+    }
+
+    #[test]
+    fn inflate_amount_adds_base_and_proportional_fee() {
+        let info = BlindedPayInfo {
+            fee_base_msat: 500,
+            fee_proportional_millionths: 10_000,
+            cltv_expiry_delta: 18,
+            htlc_minimum_msat: 1,
+        };
+
+        assert_eq!(info.inflate_amount(100_000), 101_500);
+    }
+
+    #[test]
+    fn check_minimum_rejects_amount_below_blinded_floor() {
+        let info = BlindedPayInfo {
+            fee_base_msat: 0,
+            fee_proportional_millionths: 0,
+            cltv_expiry_delta: 18,
+            htlc_minimum_msat: 10_000,
+        };
+
+        assert!(info.check_minimum(0, 9_999).is_err());
+        assert!(info.check_minimum(0, 10_000).is_ok());
+    }
+
+    #[test]
+    fn assign_route_hints_round_robins_one_hint_per_path() {
+        let mut hint_a = vec![single_hop_path(0, 0, u64::MAX).remove(0)];
+        hint_a[0].channel_name = "hint:10".to_string();
+        let mut hint_b = vec![single_hop_path(0, 0, u64::MAX).remove(0)];
+        hint_b[0].channel_name = "hint:20".to_string();
+        let hint_paths = vec![hint_a, hint_b];
+
+        let mut paths = std::collections::HashMap::from([
+            (0, single_hop_path(0, 0, u64::MAX)),
+            (1, single_hop_path(1, 0, u64::MAX)),
+            (2, single_hop_path(2, 0, u64::MAX)),
+        ]);
+
+        assign_route_hints(&mut paths, &hint_paths);
+
+        // each path gets exactly one hint hop appended (its CSV hop plus one hint hop)
+        for path_hops in paths.values() {
+            assert_eq!(path_hops.len(), 2);
+        }
+        // hints are matched round-robin, so path 2 wraps back around to hint_paths[0]
+        assert_eq!(paths[&0][1].channel_name, "hint:10");
+        assert_eq!(paths[&1][1].channel_name, "hint:20");
+        assert_eq!(paths[&2][1].channel_name, "hint:10");
+        // each appended hint hop is stamped with the path_id of the path it joined
+        assert_eq!(paths[&0][1].path_id, 0);
+        assert_eq!(paths[&1][1].path_id, 1);
+        assert_eq!(paths[&2][1].path_id, 2);
+    }
+
+    #[test]
+    fn assign_route_hints_inserts_before_blinded_introduction_node() {
+        let hint_paths = vec![vec![single_hop_path(0, 0, u64::MAX).remove(0)]];
+
+        let mut blinded_hop = single_hop_path(0, 0, u64::MAX).remove(0);
+        blinded_hop.blinded_fee_base_msat = Some(0);
+        blinded_hop.blinded_fee_proportional_millionths = Some(0);
+        blinded_hop.blinded_cltv_expiry_delta = Some(18);
+        blinded_hop.blinded_htlc_minimum_msat = Some(1);
+
+        let mut paths = std::collections::HashMap::from([(0, vec![blinded_hop])]);
+
+        assign_route_hints(&mut paths, &hint_paths);
+
+        let path_hops = &paths[&0];
+        assert_eq!(path_hops.len(), 2);
+        // the blinded introduction node must stay last, not get pushed behind the hint hop
+        assert!(BlindedPayInfo::from_hop(path_hops.last().unwrap()).is_some());
+    }
+
+    // a minimal 2-hop A -> B -> C graph with ample capacity on every edge
+    fn small_graph() -> Vec<GraphEdge> {
+        vec![
+            GraphEdge {
+                src_node: "A".to_string(),
+                dst_node: "B".to_string(),
+                channel_name: "100".to_string(),
+                capacity_msat: 1_000_000,
+                base_fee_msat: 1,
+                proportional_fee_ppm: 0,
+                cltv_delta: 40,
+                htlc_minimum_msat: 0,
+                htlc_maximum_msat: 1_000_000,
+            },
+            GraphEdge {
+                src_node: "B".to_string(),
+                dst_node: "C".to_string(),
+                channel_name: "200".to_string(),
+                capacity_msat: 1_000_000,
+                base_fee_msat: 1,
+                proportional_fee_ppm: 0,
+                cltv_delta: 40,
+                htlc_minimum_msat: 0,
+                htlc_maximum_msat: 1_000_000,
+            },
+        ]
+    }
+
+    #[test]
+    fn find_path_walks_edges_in_forward_order() {
+        let edges = small_graph();
+        let path = find_path(&edges, "A", "C", 100_000, &std::collections::HashSet::new()).unwrap();
+
+        let channel_names: Vec<&str> = path.iter().map(|e| e.channel_name.as_str()).collect();
+        assert_eq!(channel_names, vec!["100", "200"]);
+    }
+
+    #[test]
+    fn find_path_returns_none_when_no_route_exists() {
+        let edges = small_graph();
+        let path = find_path(&edges, "A", "Z", 100_000, &std::collections::HashSet::new());
+        assert!(path.is_none());
+    }
+
+    #[test]
+    fn find_mpp_paths_returns_k_paths_sized_to_per_path_share() {
+        // two parallel A -> B edges, each able to carry half of a 100_000 msat
+        // payment but not the whole thing, which is exactly the scenario MPP
+        // graph mode exists for
+        let edges = vec![
+            GraphEdge {
+                src_node: "A".to_string(),
+                dst_node: "B".to_string(),
+                channel_name: "100".to_string(),
+                capacity_msat: 60_000,
+                base_fee_msat: 1,
+                proportional_fee_ppm: 0,
+                cltv_delta: 40,
+                htlc_minimum_msat: 0,
+                htlc_maximum_msat: 60_000,
+            },
+            GraphEdge {
+                src_node: "A".to_string(),
+                dst_node: "B".to_string(),
+                channel_name: "200".to_string(),
+                capacity_msat: 60_000,
+                base_fee_msat: 1,
+                proportional_fee_ppm: 0,
+                cltv_delta: 40,
+                htlc_minimum_msat: 0,
+                htlc_maximum_msat: 60_000,
+            },
+        ];
+
+        let paths = find_mpp_paths(&edges, "A", "B", 100_000, 2).unwrap();
+
+        assert_eq!(paths.len(), 2);
+        let used_channels: std::collections::HashSet<&str> = paths
+            .iter()
+            .flat_map(|path| path.iter().map(|e| e.channel_name.as_str()))
+            .collect();
+        assert_eq!(used_channels, std::collections::HashSet::from(["100", "200"]));
+    }
+}